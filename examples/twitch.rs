@@ -6,7 +6,7 @@ use std::time::Duration;
 fn main() -> AppExit {
     let mut app = App::new();
     app.add_plugins((
-        IRCPlugin,
+        IRCPlugin::default(),
         LogPlugin::default(),
         ScheduleRunnerPlugin::run_loop(Duration::from_millis(240)),
     ));
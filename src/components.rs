@@ -1,6 +1,7 @@
 use std::{
     ops::{Deref, DerefMut},
     sync::Mutex,
+    time::Duration,
 };
 
 use bevy_ecs::prelude::*;
@@ -14,6 +15,7 @@ use crate::irc_prelude as irc;
 pub struct Connection {
     host: String,
     port: u16,
+    is_twitch: bool,
 }
 
 impl Connection {
@@ -29,6 +31,7 @@ impl Connection {
         Self {
             host: host.as_ref().to_owned(),
             port,
+            is_twitch: false,
         }
     }
 
@@ -46,6 +49,18 @@ impl Connection {
         Self {
             host: "irc.chat.twitch.tv".to_owned(),
             port: 6697,
+            is_twitch: true,
+        }
+    }
+
+    /// The [`RateLimit`] defaults appropriate for this connection: Twitch's published limits
+    /// when connected via [`Connection::twitch`], otherwise generic flood-protection-friendly
+    /// defaults
+    pub(crate) fn default_rate_limit(&self) -> RateLimit {
+        if self.is_twitch {
+            RateLimit::twitch()
+        } else {
+            RateLimit::default()
         }
     }
 }
@@ -129,6 +144,11 @@ pub struct Channels(pub Vec<String>);
 #[derive(Component, Debug)]
 pub struct Capabilities(pub Vec<irc::Capability>);
 
+/// Bevy component tracking the IRCv3 capabilities the server has acknowledged (`CAP ACK`) for
+/// this connection
+#[derive(Component, Debug, Default, Clone)]
+pub struct AckedCapabilities(pub Vec<String>);
+
 /// Bevy component containing the IRC client stream
 #[derive(Component, Debug)]
 pub struct Stream(pub(crate) irc::ClientStream);
@@ -167,14 +187,539 @@ pub(crate) struct Registered;
 #[derive(Component, Debug)]
 pub(crate) struct Identifying;
 
+/// Marks an entity currently waiting on `CAP LS`/`CAP ACK`/`CAP NAK` replies. Carries a timer so
+/// [`crate::systems::tick_cap_negotiation`] can fall back to unconditional registration if
+/// capability negotiation (including any SASL exchange) doesn't finish in time
+#[derive(Component, Debug, Default)]
+pub(crate) struct NegotiatingCaps {
+    pub(crate) timer: Stopwatch,
+}
+
+#[derive(Component, Debug)]
+pub(crate) struct CapsNegotiated;
+
+#[derive(Component, Debug, Default)]
+pub(crate) struct CapLsBuffer(pub(crate) Vec<String>);
+
+#[derive(Component, Debug)]
+pub(crate) struct Authenticating;
+
+/// Configuration for automatic reconnection with exponential backoff
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+/// use std::time::Duration;
+///
+/// let policy = ReconnectPolicy {
+///     base_delay: Duration::from_secs(1),
+///     max_delay: Duration::from_secs(300),
+///     max_attempts: None,
+///     jitter: 0.2,
+/// };
+/// ```
+#[derive(Component, Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt
+    pub base_delay: Duration,
+    /// Upper bound the exponentially growing delay is capped at
+    pub max_delay: Duration,
+    /// Maximum number of reconnect attempts before giving up, or `None` to retry forever
+    pub max_attempts: Option<u32>,
+    /// Fraction (`0.0`-`1.0`) of the computed delay to randomly add on top, to avoid a thundering herd
+    pub jitter: f32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            max_attempts: None,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub(crate) fn delay_for(&self, attempt: u32) -> Duration {
+        let exp = self.base_delay.as_secs_f64() * 2f64.powi(i32::try_from(attempt).unwrap_or(i32::MAX));
+        let capped = exp.min(self.max_delay.as_secs_f64());
+        let jitter = capped * f64::from(self.jitter) * pseudo_random_unit();
+        Duration::from_secs_f64(capped + jitter)
+    }
+}
+
+/// A cheap, non-cryptographic source of jitter; doesn't need to be a good RNG, just
+/// needs to avoid every client retrying in lockstep
+fn pseudo_random_unit() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    f64::from(nanos % 1_000_000) / 1_000_000.0
+}
+
+#[derive(Component, Debug)]
+pub(crate) struct Reconnect {
+    pub(crate) timer: Stopwatch,
+    pub(crate) attempt: u32,
+}
+
+#[derive(Component, Debug, Default)]
+pub(crate) struct ReconnectAttempts(pub(crate) u32);
+
+/// A point in history to anchor an IRCv3 CHATHISTORY request around
+#[derive(Debug, Clone)]
+pub enum HistoryAnchor {
+    /// `msgid=<id>`
+    MsgId(String),
+    /// `timestamp=<rfc3339>`
+    Timestamp(String),
+}
+
+impl std::fmt::Display for HistoryAnchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MsgId(id) => write!(f, "msgid={id}"),
+            Self::Timestamp(ts) => write!(f, "timestamp={ts}"),
+        }
+    }
+}
+
+/// Which CHATHISTORY subcommand to request, see the `draft/chathistory` IRCv3 spec
+#[derive(Debug, Clone)]
+pub enum HistorySelector {
+    /// The most recent messages
+    Latest,
+    /// Messages before the given anchor
+    Before(HistoryAnchor),
+    /// Messages after the given anchor
+    After(HistoryAnchor),
+    /// Messages between two anchors
+    Between(HistoryAnchor, HistoryAnchor),
+}
+
+/// Bevy Event requesting IRCv3 CHATHISTORY for a target, gated on the `draft/chathistory`,
+/// `server-time` and `batch` capabilities having been negotiated
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+///
+/// let request = RequestHistory::latest("#bevy", 50);
+/// ```
+#[derive(Event, Debug, Clone)]
+pub struct RequestHistory {
+    /// Channel or nick the history is requested for
+    pub target: String,
+    /// Which subcommand/anchor to request
+    pub selector: HistorySelector,
+    /// Maximum number of messages to return
+    pub limit: u32,
+}
+
+impl RequestHistory {
+    /// Request the most recent `limit` messages for `target`
+    pub fn latest(target: impl AsRef<str>, limit: u32) -> Self {
+        Self {
+            target: target.as_ref().to_owned(),
+            selector: HistorySelector::Latest,
+            limit,
+        }
+    }
+
+    /// Request `limit` messages for `target` before the given anchor
+    pub fn before(target: impl AsRef<str>, anchor: HistoryAnchor, limit: u32) -> Self {
+        Self {
+            target: target.as_ref().to_owned(),
+            selector: HistorySelector::Before(anchor),
+            limit,
+        }
+    }
+
+    /// Request `limit` messages for `target` after the given anchor
+    pub fn after(target: impl AsRef<str>, anchor: HistoryAnchor, limit: u32) -> Self {
+        Self {
+            target: target.as_ref().to_owned(),
+            selector: HistorySelector::After(anchor),
+            limit,
+        }
+    }
+
+    /// Request `limit` messages for `target` between two anchors
+    pub fn between(target: impl AsRef<str>, from: HistoryAnchor, to: HistoryAnchor, limit: u32) -> Self {
+        Self {
+            target: target.as_ref().to_owned(),
+            selector: HistorySelector::Between(from, to),
+            limit,
+        }
+    }
+
+    pub(crate) fn into_command(self) -> irc::Command {
+        let (sub, mut args) = match self.selector {
+            HistorySelector::Latest => ("LATEST", vec![self.target, "*".to_owned()]),
+            HistorySelector::Before(anchor) => ("BEFORE", vec![self.target, anchor.to_string()]),
+            HistorySelector::After(anchor) => ("AFTER", vec![self.target, anchor.to_string()]),
+            HistorySelector::Between(from, to) => (
+                "BETWEEN",
+                vec![self.target, from.to_string(), to.to_string()],
+            ),
+        };
+        args.push(self.limit.to_string());
+        let mut full_args = vec![sub.to_owned()];
+        full_args.extend(args);
+        irc::Command::Raw("CHATHISTORY".to_owned(), full_args)
+    }
+}
+
+/// Bevy Event emitted once all messages of a CHATHISTORY batch have been collected
+#[derive(Event, Debug, Clone)]
+pub struct HistoryBatch {
+    /// Channel or nick the history was requested for
+    pub target: String,
+    /// Messages in the batch, in the order they were received
+    pub messages: Vec<irc::Message>,
+}
+
+#[derive(Debug)]
+pub(crate) struct OpenBatch {
+    pub(crate) target: String,
+    pub(crate) messages: Vec<irc::Message>,
+}
+
+#[derive(Component, Debug, Default)]
+pub(crate) struct OpenBatches(pub(crate) std::collections::HashMap<String, OpenBatch>);
+
+/// SASL authentication mechanism
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaslMechanism {
+    /// `PLAIN` mechanism, authenticating with an authcid/password pair
+    #[default]
+    Plain,
+    /// `EXTERNAL` mechanism, authenticating using an identity already established
+    /// out-of-band (e.g. a TLS client certificate)
+    External,
+}
+
+impl SaslMechanism {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Plain => "PLAIN",
+            Self::External => "EXTERNAL",
+        }
+    }
+}
+
+/// Bevy component requesting IRCv3 SASL authentication during capability negotiation
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+///
+/// let sasl = Sasl::plain("account", "password");
+/// ```
+#[derive(Component, Debug, Clone)]
+pub struct Sasl {
+    /// Authentication mechanism to use
+    pub mechanism: SaslMechanism,
+    /// Authorization identity, usually left empty to default to the authentication identity
+    pub authzid: String,
+    /// Authentication identity (account name)
+    pub authcid: String,
+    /// Password used for the `PLAIN` mechanism
+    pub password: Option<String>,
+}
+
+impl Sasl {
+    /// Create `PLAIN` SASL credentials for the given account and password
+    pub fn plain(authcid: impl AsRef<str>, password: impl AsRef<str>) -> Self {
+        Self {
+            mechanism: SaslMechanism::Plain,
+            authzid: String::new(),
+            authcid: authcid.as_ref().to_owned(),
+            password: Some(password.as_ref().to_owned()),
+        }
+    }
+
+    /// Create `EXTERNAL` SASL credentials (e.g. for TLS client certificate authentication)
+    pub fn external(authcid: impl AsRef<str>) -> Self {
+        Self {
+            mechanism: SaslMechanism::External,
+            authzid: String::new(),
+            authcid: authcid.as_ref().to_owned(),
+            password: None,
+        }
+    }
+
+    /// Set the authorization identity
+    #[inline]
+    #[must_use]
+    pub fn authzid(self, authzid: impl AsRef<str>) -> Self {
+        Self {
+            authzid: authzid.as_ref().to_owned(),
+            ..self
+        }
+    }
+
+    pub(crate) fn encode_response(&self) -> String {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        match self.mechanism {
+            SaslMechanism::External => String::new(),
+            SaslMechanism::Plain => {
+                let password = self.password.as_deref().unwrap_or_default();
+                let mut payload = Vec::with_capacity(
+                    self.authzid.len() + self.authcid.len() + password.len() + 2,
+                );
+                payload.extend_from_slice(self.authzid.as_bytes());
+                payload.push(0);
+                payload.extend_from_slice(self.authcid.as_bytes());
+                payload.push(0);
+                payload.extend_from_slice(password.as_bytes());
+                STANDARD.encode(payload)
+            }
+        }
+    }
+}
+
 #[derive(Event, Debug, Default)]
 pub(crate) struct Pinger {
     pub(crate) last_ping: Stopwatch,
 }
 
-/// Bevy Event for incoming IRC messages and commands
+/// Delimiter marking a CTCP payload inside a `PRIVMSG`/`NOTICE` trailing parameter
+const CTCP_DELIM: char = '\u{1}';
+
+/// Low-level CTCP quoting, escaping `\x10`, `\x01`, `\n` and `\r` so they survive as literal
+/// payload bytes instead of being mistaken for the CTCP delimiter or line terminators
+pub(crate) fn ctcp_quote(payload: &str) -> String {
+    let mut out = String::with_capacity(payload.len());
+    for c in payload.chars() {
+        match c {
+            '\u{10}' => out.push_str("\u{10}\u{10}"),
+            CTCP_DELIM => out.push_str("\u{10}a"),
+            '\n' => out.push_str("\u{10}n"),
+            '\r' => out.push_str("\u{10}r"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reverses [`ctcp_quote`]'s `\x10` escaping
+pub(crate) fn ctcp_dequote(payload: &str) -> String {
+    let mut out = String::with_capacity(payload.len());
+    let mut chars = payload.chars();
+    while let Some(c) = chars.next() {
+        if c != '\u{10}' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\u{10}') => out.push('\u{10}'),
+            Some('a') => out.push(CTCP_DELIM),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Quotes a CTCP payload with [`ctcp_quote`] and wraps it in the `\x01` delimiters expected on
+/// the wire
+pub(crate) fn ctcp_wrap(payload: &str) -> String {
+    format!("{CTCP_DELIM}{}{CTCP_DELIM}", ctcp_quote(payload))
+}
+
+/// A parsed CTCP command, extracted from a `PRIVMSG`/`NOTICE` trailing parameter wrapped in
+/// `\x01` (see the [CTCP spec](https://modern.ircdocs.horse/ctcp))
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CtcpCommand {
+    /// `PING <token>`, echoed back verbatim in a reply to measure round-trip time
+    Ping(Option<String>),
+    /// `VERSION`
+    Version,
+    /// `TIME`
+    Time,
+    /// `CLIENTINFO`
+    ClientInfo,
+    /// `ACTION <text>`, i.e. a "/me" action
+    Action(String),
+    /// Any other CTCP command, with its raw argument string if one was given
+    Other(String, Option<String>),
+}
+
+impl CtcpCommand {
+    pub(crate) fn parse(payload: &str) -> Self {
+        let (cmd, arg) = match payload.split_once(' ') {
+            Some((cmd, arg)) => (cmd, Some(arg.to_owned())),
+            None => (payload, None),
+        };
+        match cmd.to_ascii_uppercase().as_str() {
+            "PING" => Self::Ping(arg),
+            "VERSION" => Self::Version,
+            "TIME" => Self::Time,
+            "CLIENTINFO" => Self::ClientInfo,
+            "ACTION" => Self::Action(arg.unwrap_or_default()),
+            _ => Self::Other(cmd.to_owned(), arg),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        match self {
+            Self::Ping(Some(token)) => format!("PING {token}"),
+            Self::Ping(None) => "PING".to_owned(),
+            Self::Version => "VERSION".to_owned(),
+            Self::Time => "TIME".to_owned(),
+            Self::ClientInfo => "CLIENTINFO".to_owned(),
+            Self::Action(text) => format!("ACTION {text}"),
+            Self::Other(cmd, Some(arg)) => format!("{cmd} {arg}"),
+            Self::Other(cmd, None) => cmd.clone(),
+        }
+    }
+}
+
+/// A parsed incoming CTCP query or reply, delivered as an [`Incoming<Ctcp>`] event/trigger
+/// alongside the untyped [`Incoming<irc::Message>`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ctcp {
+    /// Channel or nick the carrying `PRIVMSG`/`NOTICE` was sent to
+    pub target: String,
+    /// Nickname that sent the CTCP, if the carrying message had one
+    pub from: Option<String>,
+    /// `true` if this was carried by a `NOTICE` (a CTCP reply) rather than a `PRIVMSG` (a query)
+    pub is_reply: bool,
+    /// The parsed command
+    pub command: CtcpCommand,
+}
+
+/// Bevy component configuring automatic replies to incoming CTCP queries
+///
+/// Absent this component, incoming CTCP is still parsed and emitted as [`Incoming<Ctcp>`], but
+/// never auto-replied to.
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+///
+/// let ctcp = CtcpConfig::new("bevy_irc 0.1").reply_time(false);
+/// ```
+#[derive(Component, Debug, Clone)]
+pub struct CtcpConfig {
+    /// Version string reported in reply to `VERSION`
+    pub client_version: String,
+    /// Whether to auto-reply to `VERSION`
+    pub reply_version: bool,
+    /// Whether to auto-reply to `TIME`
+    pub reply_time: bool,
+    /// Whether to auto-reply to `PING`
+    pub reply_ping: bool,
+    /// Whether to auto-reply to `CLIENTINFO`
+    pub reply_client_info: bool,
+}
+
+impl CtcpConfig {
+    /// Create a CTCP config replying to all standard queries, reporting `version` for `VERSION`
+    pub fn new(version: impl AsRef<str>) -> Self {
+        Self {
+            client_version: version.as_ref().to_owned(),
+            reply_version: true,
+            reply_time: true,
+            reply_ping: true,
+            reply_client_info: true,
+        }
+    }
+
+    /// Enable or disable auto-replying to `VERSION`
+    #[inline]
+    #[must_use]
+    pub fn reply_version(self, enabled: bool) -> Self {
+        Self {
+            reply_version: enabled,
+            ..self
+        }
+    }
+
+    /// Enable or disable auto-replying to `TIME`
+    #[inline]
+    #[must_use]
+    pub fn reply_time(self, enabled: bool) -> Self {
+        Self {
+            reply_time: enabled,
+            ..self
+        }
+    }
+
+    /// Enable or disable auto-replying to `PING`
+    #[inline]
+    #[must_use]
+    pub fn reply_ping(self, enabled: bool) -> Self {
+        Self {
+            reply_ping: enabled,
+            ..self
+        }
+    }
+
+    /// Enable or disable auto-replying to `CLIENTINFO`
+    #[inline]
+    #[must_use]
+    pub fn reply_client_info(self, enabled: bool) -> Self {
+        Self {
+            reply_client_info: enabled,
+            ..self
+        }
+    }
+
+    /// The reply for a standard query enabled by this config, or `None` if it's non-standard or
+    /// disabled
+    pub(crate) fn reply_for(&self, command: &CtcpCommand) -> Option<CtcpCommand> {
+        match command {
+            CtcpCommand::Ping(token) if self.reply_ping => Some(CtcpCommand::Ping(token.clone())),
+            CtcpCommand::Version if self.reply_version => Some(CtcpCommand::Other(
+                "VERSION".to_owned(),
+                Some(self.client_version.clone()),
+            )),
+            CtcpCommand::Time if self.reply_time => Some(CtcpCommand::Other("TIME".to_owned(), Some(ctcp_time_now()))),
+            CtcpCommand::ClientInfo if self.reply_client_info => Some(CtcpCommand::Other(
+                "CLIENTINFO".to_owned(),
+                Some("ACTION CLIENTINFO PING TIME VERSION".to_owned()),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A coarse, dependency-free `TIME` reply: seconds since the Unix epoch
+fn ctcp_time_now() -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Bevy Event emitted when an entity's connection is lost, before a reconnect is scheduled
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Disconnected {
+    /// Entity whose connection was lost
+    pub entity: Entity,
+}
+
+/// Bevy Event emitted when IRCv3 SASL authentication fails
+#[derive(Event, Debug, Clone)]
+pub struct SaslFailed {
+    /// Numeric reply that reported the failure
+    pub numeric: irc::Response,
+}
+
+/// Bevy Event for incoming IRC messages and commands. Defaults to the raw [`irc::Message`], but
+/// is also used with other payloads (e.g. [`Ctcp`]) for typed dispatch of a specific subsystem
 #[derive(Event, Debug, Clone)]
-pub struct Incoming<T>(pub(crate) T);
+pub struct Incoming<T = irc::Message>(pub(crate) T);
 
 impl<T> Deref for Incoming<T> {
     type Target = T;
@@ -212,4 +757,579 @@ impl Outgoing<irc::Message> {
     pub fn new(message: irc::Message) -> Self {
         Self(message)
     }
+
+    /// Build a CTCP `ACTION` (i.e. "/me") message to `target`, with proper `\x01` quoting
+    ///
+    /// # Example
+    /// ```
+    /// use bevy_irc::prelude::*;
+    ///
+    /// let action = Outgoing::<irc::Message>::ctcp_action("#bevy", "waves");
+    /// ```
+    #[must_use]
+    pub fn ctcp_action(target: impl AsRef<str>, text: impl AsRef<str>) -> Self {
+        let payload = ctcp_wrap(&CtcpCommand::Action(text.as_ref().to_owned()).encode());
+        Self(irc::Message {
+            tags: None,
+            prefix: None,
+            command: irc::Command::PRIVMSG(target.as_ref().to_owned(), payload),
+        })
+    }
+
+    /// Build a CTCP `PING` request to `target`, with proper `\x01` quoting. `token` is echoed
+    /// back verbatim in the reply and can be used to measure round-trip time
+    ///
+    /// # Example
+    /// ```
+    /// use bevy_irc::prelude::*;
+    ///
+    /// let ping = Outgoing::<irc::Message>::ctcp_ping("bevy_user", "1234");
+    /// ```
+    #[must_use]
+    pub fn ctcp_ping(target: impl AsRef<str>, token: impl AsRef<str>) -> Self {
+        let payload = ctcp_wrap(&CtcpCommand::Ping(Some(token.as_ref().to_owned())).encode());
+        Self(irc::Message {
+            tags: None,
+            prefix: None,
+            command: irc::Command::PRIVMSG(target.as_ref().to_owned(), payload),
+        })
+    }
+}
+
+/// Class of outgoing command, used to pick which token bucket in a [`RateLimit`] governs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum CommandClass {
+    /// `PRIVMSG`/`NOTICE`
+    Message,
+    /// `JOIN`
+    Join,
+    /// Everything else
+    Generic,
+}
+
+impl CommandClass {
+    pub(crate) fn of(command: &irc::Command) -> Self {
+        match command {
+            irc::Command::PRIVMSG(..) | irc::Command::NOTICE(..) => Self::Message,
+            irc::Command::JOIN(..) => Self::Join,
+            _ => Self::Generic,
+        }
+    }
+}
+
+/// A single token-bucket limit: up to `capacity` tokens, refilling `refill` tokens every `period`
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+/// use std::time::Duration;
+///
+/// let limit = TokenBucketLimit {
+///     capacity: 20,
+///     refill: 20,
+///     period: Duration::from_secs(30),
+/// };
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucketLimit {
+    /// Maximum number of tokens the bucket can hold, i.e. the burst capacity
+    pub capacity: u32,
+    /// Tokens added back per `period`
+    pub refill: u32,
+    /// How often `refill` tokens are added back
+    pub period: Duration,
+}
+
+impl TokenBucketLimit {
+    fn tokens_per_sec(&self) -> f64 {
+        f64::from(self.refill) / self.period.as_secs_f64()
+    }
+}
+
+/// Bevy component configuring the per-command-class token buckets that govern outgoing message
+/// throughput, so the client doesn't trip a network's flood protection
+///
+/// Absent this component, one is inserted on connect: [`RateLimit::twitch`] for a
+/// [`Connection::twitch`] connection, [`RateLimit::default`] otherwise.
+///
+/// # Example
+/// ```
+/// use bevy_irc::prelude::*;
+///
+/// let limits = RateLimit::twitch();
+/// ```
+#[derive(Component, Debug, Clone)]
+pub struct RateLimit {
+    /// Bucket governing `PRIVMSG`/`NOTICE`
+    pub message: TokenBucketLimit,
+    /// Bucket governing `JOIN`
+    pub join: TokenBucketLimit,
+    /// Bucket governing everything else
+    pub generic: TokenBucketLimit,
+}
+
+impl Default for RateLimit {
+    /// Conservative defaults suitable for typical IRC networks' flood protection
+    fn default() -> Self {
+        Self {
+            message: TokenBucketLimit {
+                capacity: 5,
+                refill: 5,
+                period: Duration::from_secs(8),
+            },
+            join: TokenBucketLimit {
+                capacity: 10,
+                refill: 10,
+                period: Duration::from_secs(10),
+            },
+            generic: TokenBucketLimit {
+                capacity: 10,
+                refill: 10,
+                period: Duration::from_secs(5),
+            },
+        }
+    }
+}
+
+impl RateLimit {
+    /// Twitch's published limits for a normal (non-verified) chat bot: 20 messages per 30s,
+    /// joins capped at 20 per 10s
+    #[must_use]
+    pub fn twitch() -> Self {
+        Self {
+            message: TokenBucketLimit {
+                capacity: 20,
+                refill: 20,
+                period: Duration::from_secs(30),
+            },
+            join: TokenBucketLimit {
+                capacity: 20,
+                refill: 20,
+                period: Duration::from_secs(10),
+            },
+            generic: TokenBucketLimit {
+                capacity: 20,
+                refill: 20,
+                period: Duration::from_secs(30),
+            },
+        }
+    }
+
+    /// Twitch's higher limits granted to known/verified bots and moderators: 100 messages per
+    /// 30s. This crate doesn't watch incoming messages for moderator status itself; swap this in
+    /// manually (e.g. `commands.entity(id).insert(RateLimit::twitch_verified())`) once the caller
+    /// has confirmed the account's elevated status, for example by checking
+    /// [`TwitchMessageExt::is_send_by_mod`](crate::twitch::TwitchMessageExt::is_send_by_mod) on a
+    /// `USERSTATE` for the bot's own nick
+    #[must_use]
+    pub fn twitch_verified() -> Self {
+        Self {
+            message: TokenBucketLimit {
+                capacity: 100,
+                refill: 100,
+                period: Duration::from_secs(30),
+            },
+            ..Self::twitch()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+}
+
+impl TokenBucket {
+    fn full(limit: &TokenBucketLimit) -> Self {
+        Self {
+            tokens: f64::from(limit.capacity),
+        }
+    }
+
+    fn refill(&mut self, limit: &TokenBucketLimit, elapsed: Duration) {
+        self.tokens = (self.tokens + limit.tokens_per_sec() * elapsed.as_secs_f64()).min(f64::from(limit.capacity));
+    }
+
+    fn try_take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Per-entity token-bucket state backing a [`RateLimit`], seeded from it on connect
+#[derive(Component, Debug)]
+pub(crate) struct BucketStates {
+    message: TokenBucket,
+    join: TokenBucket,
+    generic: TokenBucket,
+}
+
+impl BucketStates {
+    pub(crate) fn new(limits: &RateLimit) -> Self {
+        Self {
+            message: TokenBucket::full(&limits.message),
+            join: TokenBucket::full(&limits.join),
+            generic: TokenBucket::full(&limits.generic),
+        }
+    }
+
+    pub(crate) fn refill(&mut self, limits: &RateLimit, elapsed: Duration) {
+        self.message.refill(&limits.message, elapsed);
+        self.join.refill(&limits.join, elapsed);
+        self.generic.refill(&limits.generic, elapsed);
+    }
+
+    pub(crate) fn try_take(&mut self, class: CommandClass) -> bool {
+        match class {
+            CommandClass::Message => self.message.try_take(),
+            CommandClass::Join => self.join.try_take(),
+            CommandClass::Generic => self.generic.try_take(),
+        }
+    }
+}
+
+/// Bevy component buffering outgoing messages until [`drain_send_queue`](crate::systems::drain_send_queue)
+/// releases them as token-bucket capacity allows
+///
+/// Messages are partitioned into one queue per [`CommandClass`] so a bucket that's out of tokens
+/// for one class (e.g. `message`) doesn't block messages of another class (e.g. `join`) that
+/// still has capacity.
+#[derive(Component, Debug, Default)]
+pub(crate) struct SendQueue {
+    message: std::collections::VecDeque<irc::Message>,
+    join: std::collections::VecDeque<irc::Message>,
+    generic: std::collections::VecDeque<irc::Message>,
+}
+
+impl SendQueue {
+    pub(crate) fn push(&mut self, class: CommandClass, message: irc::Message) {
+        self.queue_mut(class).push_back(message);
+    }
+
+    pub(crate) fn queue_mut(&mut self, class: CommandClass) -> &mut std::collections::VecDeque<irc::Message> {
+        match class {
+            CommandClass::Message => &mut self.message,
+            CommandClass::Join => &mut self.join,
+            CommandClass::Generic => &mut self.generic,
+        }
+    }
+}
+
+/// A parsed `PRIVMSG`, emitted as `Incoming<PrivMsg>` once opted into with
+/// [`IRCPlugin::with_privmsg_events`](crate::IRCPlugin::with_privmsg_events)
+#[derive(Debug, Clone)]
+pub struct PrivMsg {
+    /// Channel or nick the `PRIVMSG` was sent to
+    pub target: String,
+    /// Message text
+    pub text: String,
+    /// Nickname that sent it, if the carrying message had one
+    pub from: Option<String>,
+}
+
+/// A parsed `JOIN`, emitted as `Incoming<Join>` once opted into with
+/// [`IRCPlugin::with_join_events`](crate::IRCPlugin::with_join_events)
+#[derive(Debug, Clone)]
+pub struct Join {
+    /// Channel that was joined
+    pub channel: String,
+    /// Nickname that joined, if the carrying message had one
+    pub who: Option<String>,
+}
+
+/// A parsed `NICK` change, emitted as `Incoming<NickChange>` once opted into with
+/// [`IRCPlugin::with_nick_change_events`](crate::IRCPlugin::with_nick_change_events)
+#[derive(Debug, Clone)]
+pub struct NickChange {
+    /// Nickname being changed from, if the carrying message had one
+    pub old_nick: Option<String>,
+    /// Nickname being changed to
+    pub new_nick: String,
+}
+
+/// A parsed numeric reply, emitted as `Incoming<Numeric>` once opted into with
+/// [`IRCPlugin::with_numeric_events`](crate::IRCPlugin::with_numeric_events)
+#[derive(Debug, Clone)]
+pub struct Numeric {
+    /// The numeric reply
+    pub numeric: irc::Response,
+    /// Its parameters
+    pub args: Vec<String>,
+}
+
+/// Which typed, per-command `Incoming<T>` events [`IRCPlugin`](crate::IRCPlugin) emits in
+/// addition to the generic `Incoming<irc::Message>`. All default to disabled, keeping
+/// `dispatch_typed_events` cheap until a user opts in to the ones they want
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub(crate) struct TypedEvents {
+    pub(crate) privmsg: bool,
+    pub(crate) join: bool,
+    pub(crate) nick_change: bool,
+    pub(crate) numeric: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_for_first_attempt_is_base_delay() {
+        assert_eq!(policy(0.0).delay_for(0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn delay_for_doubles_with_each_attempt() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay_for(1), Duration::from_secs(2));
+        assert_eq!(policy.delay_for(2), Duration::from_secs(4));
+        assert_eq!(policy.delay_for(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn delay_for_is_capped_at_max_delay() {
+        let policy = policy(0.0);
+        assert_eq!(policy.delay_for(10), policy.max_delay);
+        assert_eq!(policy.delay_for(u32::MAX), policy.max_delay);
+    }
+
+    #[test]
+    fn delay_for_adds_at_most_jitter_fraction_on_top_of_the_capped_delay() {
+        let policy = policy(0.2);
+        let delay = policy.delay_for(10);
+        assert!(delay >= policy.max_delay);
+        assert!(delay <= policy.max_delay.mul_f64(1.2));
+    }
+
+    fn limit(capacity: u32, refill: u32, period: Duration) -> TokenBucketLimit {
+        TokenBucketLimit {
+            capacity,
+            refill,
+            period,
+        }
+    }
+
+    #[test]
+    fn token_bucket_starts_full() {
+        let limit = limit(5, 5, Duration::from_secs(10));
+        let mut bucket = TokenBucket::full(&limit);
+        for _ in 0..5 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn token_bucket_refills_proportionally_to_elapsed_time() {
+        let limit = limit(10, 10, Duration::from_secs(10));
+        let mut bucket = TokenBucket::full(&limit);
+        for _ in 0..10 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+
+        // half the refill period has passed: half the tokens come back
+        bucket.refill(&limit, Duration::from_secs(5));
+        for _ in 0..5 {
+            assert!(bucket.try_take());
+        }
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn token_bucket_refill_is_capped_at_capacity() {
+        let limit = limit(5, 5, Duration::from_secs(1));
+        let mut bucket = TokenBucket::full(&limit);
+        bucket.refill(&limit, Duration::from_secs(100));
+        let mut taken = 0;
+        while bucket.try_take() {
+            taken += 1;
+        }
+        assert_eq!(taken, 5);
+    }
+
+    #[test]
+    fn ctcp_quote_round_trips_delimiter_and_control_bytes() {
+        let payload = "VERSION bevy_irc\u{1}\u{10}\n\r";
+        assert_eq!(ctcp_dequote(&ctcp_quote(payload)), payload);
+    }
+
+    #[test]
+    fn ctcp_quote_escapes_the_delimiter_and_newlines() {
+        assert_eq!(ctcp_quote("\u{10}"), "\u{10}\u{10}");
+        assert_eq!(ctcp_quote("\u{1}"), "\u{10}a");
+        assert_eq!(ctcp_quote("\n"), "\u{10}n");
+        assert_eq!(ctcp_quote("\r"), "\u{10}r");
+    }
+
+    #[test]
+    fn ctcp_quote_leaves_plain_text_untouched() {
+        assert_eq!(ctcp_quote("ACTION waves"), "ACTION waves");
+    }
+
+    #[test]
+    fn ctcp_dequote_drops_an_unescaped_trailing_marker() {
+        // a stray trailing `\x10` with nothing after it is dropped rather than panicking
+        assert_eq!(ctcp_dequote("abc\u{10}"), "abc");
+    }
+
+    #[test]
+    fn ctcp_wrap_delimits_the_quoted_payload() {
+        assert_eq!(ctcp_wrap("VERSION"), "\u{1}VERSION\u{1}");
+    }
+
+    #[test]
+    fn ctcp_command_parse_is_case_insensitive() {
+        assert_eq!(CtcpCommand::parse("version"), CtcpCommand::Version);
+        assert_eq!(CtcpCommand::parse("VeRsIoN"), CtcpCommand::Version);
+    }
+
+    #[test]
+    fn ctcp_command_parse_standard_commands_without_args() {
+        assert_eq!(CtcpCommand::parse("VERSION"), CtcpCommand::Version);
+        assert_eq!(CtcpCommand::parse("TIME"), CtcpCommand::Time);
+        assert_eq!(CtcpCommand::parse("CLIENTINFO"), CtcpCommand::ClientInfo);
+        assert_eq!(CtcpCommand::parse("PING"), CtcpCommand::Ping(None));
+    }
+
+    #[test]
+    fn ctcp_command_parse_ping_keeps_its_token() {
+        assert_eq!(
+            CtcpCommand::parse("PING 1234"),
+            CtcpCommand::Ping(Some("1234".to_owned()))
+        );
+    }
+
+    #[test]
+    fn ctcp_command_parse_action_defaults_to_an_empty_string_without_text() {
+        assert_eq!(CtcpCommand::parse("ACTION"), CtcpCommand::Action(String::new()));
+        assert_eq!(
+            CtcpCommand::parse("ACTION waves"),
+            CtcpCommand::Action("waves".to_owned())
+        );
+    }
+
+    #[test]
+    fn ctcp_command_parse_unknown_command_is_preserved_verbatim_with_its_arg() {
+        assert_eq!(
+            CtcpCommand::parse("FINGER"),
+            CtcpCommand::Other("FINGER".to_owned(), None)
+        );
+        assert_eq!(
+            CtcpCommand::parse("FINGER plan"),
+            CtcpCommand::Other("FINGER".to_owned(), Some("plan".to_owned()))
+        );
+    }
+
+    #[test]
+    fn ctcp_command_encode_round_trips_parse() {
+        for encoded in ["VERSION", "TIME", "CLIENTINFO", "PING", "PING 1234", "ACTION waves", "FINGER plan"] {
+            assert_eq!(CtcpCommand::parse(encoded).encode(), encoded);
+        }
+    }
+
+    fn ctcp_config(enable: bool) -> CtcpConfig {
+        CtcpConfig::new("bevy_irc 0.1")
+            .reply_version(enable)
+            .reply_time(enable)
+            .reply_ping(enable)
+            .reply_client_info(enable)
+    }
+
+    #[test]
+    fn reply_for_ping_respects_its_toggle() {
+        let query = CtcpCommand::Ping(Some("1234".to_owned()));
+        assert_eq!(ctcp_config(true).reply_for(&query), Some(query.clone()));
+        assert_eq!(ctcp_config(false).reply_for(&query), None);
+    }
+
+    #[test]
+    fn reply_for_version_respects_its_toggle_and_uses_the_configured_string() {
+        assert_eq!(
+            ctcp_config(true).reply_for(&CtcpCommand::Version),
+            Some(CtcpCommand::Other("VERSION".to_owned(), Some("bevy_irc 0.1".to_owned())))
+        );
+        assert_eq!(ctcp_config(false).reply_for(&CtcpCommand::Version), None);
+    }
+
+    #[test]
+    fn reply_for_time_respects_its_toggle() {
+        assert!(ctcp_config(true).reply_for(&CtcpCommand::Time).is_some());
+        assert_eq!(ctcp_config(false).reply_for(&CtcpCommand::Time), None);
+    }
+
+    #[test]
+    fn reply_for_client_info_respects_its_toggle() {
+        assert!(ctcp_config(true).reply_for(&CtcpCommand::ClientInfo).is_some());
+        assert_eq!(ctcp_config(false).reply_for(&CtcpCommand::ClientInfo), None);
+    }
+
+    #[test]
+    fn reply_for_non_standard_command_is_always_none() {
+        let other = CtcpCommand::Other("FINGER".to_owned(), None);
+        assert_eq!(ctcp_config(true).reply_for(&other), None);
+    }
+
+    fn raw_args(command: irc::Command) -> (String, Vec<String>) {
+        match command {
+            irc::Command::Raw(cmd, args) => (cmd, args),
+            other => panic!("expected a Raw command, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn request_history_latest_requests_the_target_with_a_wildcard_anchor_and_a_limit() {
+        let (cmd, args) = raw_args(RequestHistory::latest("#bevy", 50).into_command());
+        assert_eq!(cmd, "CHATHISTORY");
+        assert_eq!(args, vec!["LATEST", "#bevy", "*", "50"]);
+    }
+
+    #[test]
+    fn request_history_before_requests_the_anchor_with_a_limit() {
+        let (cmd, args) = raw_args(
+            RequestHistory::before("#bevy", HistoryAnchor::MsgId("abc123".to_owned()), 20).into_command(),
+        );
+        assert_eq!(cmd, "CHATHISTORY");
+        assert_eq!(args, vec!["BEFORE", "#bevy", "msgid=abc123", "20"]);
+    }
+
+    #[test]
+    fn request_history_after_requests_the_anchor_with_a_limit() {
+        let (cmd, args) = raw_args(
+            RequestHistory::after("#bevy", HistoryAnchor::Timestamp("2024-01-01T00:00:00Z".to_owned()), 20)
+                .into_command(),
+        );
+        assert_eq!(cmd, "CHATHISTORY");
+        assert_eq!(
+            args,
+            vec!["AFTER", "#bevy", "timestamp=2024-01-01T00:00:00Z", "20"]
+        );
+    }
+
+    #[test]
+    fn request_history_between_requests_both_anchors_in_order_with_a_limit() {
+        let (cmd, args) = raw_args(
+            RequestHistory::between(
+                "#bevy",
+                HistoryAnchor::MsgId("from".to_owned()),
+                HistoryAnchor::MsgId("to".to_owned()),
+                10,
+            )
+            .into_command(),
+        );
+        assert_eq!(cmd, "CHATHISTORY");
+        assert_eq!(args, vec!["BETWEEN", "#bevy", "msgid=from", "msgid=to", "10"]);
+    }
 }
@@ -0,0 +1,170 @@
+//! Loading [`Connection`]/[`Auth`]/[`Channels`] from the `irc` crate's declarative config files
+
+use std::path::Path;
+
+use bevy_utils::tracing::warn;
+
+use crate::components::{Auth, Capabilities, Channels, Connection};
+use crate::irc_prelude as irc;
+
+/// Error returned by [`load`] and [`load_from`]
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read or deserialized as an `irc::Config`
+    #[error("failed to load irc config: {0}")]
+    Irc(#[from] irc::Error),
+    /// The config is missing a `server`, which [`Connection`] requires
+    #[error("config is missing a `server`")]
+    MissingServer,
+    /// The config is missing a `nickname`, which [`Auth`] requires
+    #[error("config is missing a `nickname`")]
+    MissingNickname,
+}
+
+/// Reads an `irc::Config` from a TOML or JSON file (selected by extension, see
+/// `irc::Config::load`) and builds the components for a connection described by it
+///
+/// # Example
+/// ```no_run
+/// use bevy_irc::prelude::*;
+/// use bevy_app::prelude::*;
+///
+/// let mut app = App::new();
+/// let bundle = bevy_irc::config::load("bot.toml").unwrap();
+/// app.world_mut().spawn(bundle);
+/// ```
+pub fn load(
+    path: impl AsRef<Path>,
+) -> Result<(Connection, Auth, Channels, Capabilities), ConfigError> {
+    load_from(&irc::Config::load(path)?)
+}
+
+/// As [`load`], but from an already-parsed `irc::Config`
+///
+/// Rounds-trips the fields [`From<&Connection> for irc::Config`](crate::components::Connection)
+/// already maps (`server`/`port`) plus `nickname`/`password`/`channels`. `ping_time` is not one of
+/// them: this crate always disables the client's built-in pinger (`ping_time: u32::MAX`) in
+/// favor of driving `PING`/`PONG` itself, so a `ping_time` set in the file can't be honored; we
+/// warn rather than silently drop it.
+/// `irc::Config` has no equivalent of [`Capabilities`], so it's always returned empty; add
+/// capabilities to the entity after spawning if the connection needs them.
+pub fn load_from(
+    config: &irc::Config,
+) -> Result<(Connection, Auth, Channels, Capabilities), ConfigError> {
+    let server = config.server.clone().ok_or(ConfigError::MissingServer)?;
+    let port = config.port.unwrap_or(6667);
+    let nickname = config
+        .nickname
+        .clone()
+        .ok_or(ConfigError::MissingNickname)?;
+
+    if let Some(ping_time) = config.ping_time {
+        warn!(
+            message = "Ignoring `ping_time` from config: bevy_irc always disables the client's \
+                        built-in pinger and drives PING/PONG itself",
+            ping_time
+        );
+    }
+
+    let connection = Connection::new(server, port);
+
+    let mut auth = Auth::new(nickname);
+    if let Some(password) = &config.password {
+        auth = auth.password(password);
+    }
+
+    let channels = Channels(config.channels.clone().unwrap_or_default());
+
+    Ok((connection, auth, channels, Capabilities(Vec::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(server: Option<&str>, nickname: Option<&str>) -> irc::Config {
+        irc::Config {
+            server: server.map(ToOwned::to_owned),
+            nickname: nickname.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn missing_server_is_an_error() {
+        assert!(matches!(
+            load_from(&config(None, Some("bevy_bot"))),
+            Err(ConfigError::MissingServer)
+        ));
+    }
+
+    #[test]
+    fn missing_nickname_is_an_error() {
+        assert!(matches!(
+            load_from(&config(Some("irc.example.org"), None)),
+            Err(ConfigError::MissingNickname)
+        ));
+    }
+
+    #[test]
+    fn server_and_port_round_trip_through_connection() {
+        let mut cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        cfg.port = Some(6697);
+        let (connection, ..) = load_from(&cfg).unwrap();
+        let roundtripped = irc::Config::from(&connection);
+        assert_eq!(roundtripped.server, Some("irc.example.org".to_owned()));
+        assert_eq!(roundtripped.port, Some(6697));
+    }
+
+    #[test]
+    fn missing_port_defaults_to_6667() {
+        let cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        let (connection, ..) = load_from(&cfg).unwrap();
+        assert_eq!(irc::Config::from(&connection).port, Some(6667));
+    }
+
+    #[test]
+    fn nickname_and_password_map_to_auth() {
+        let mut cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        cfg.password = Some("hunter2".to_owned());
+        let (_, auth, ..) = load_from(&cfg).unwrap();
+        assert_eq!(auth.nick, "bevy_bot");
+        assert_eq!(auth.pass, Some("hunter2".to_owned()));
+    }
+
+    #[test]
+    fn missing_password_leaves_auth_unset() {
+        let cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        let (_, auth, ..) = load_from(&cfg).unwrap();
+        assert_eq!(auth.pass, None);
+    }
+
+    #[test]
+    fn channels_map_from_config() {
+        let mut cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        cfg.channels = Some(vec!["#bevy".to_owned(), "#rust".to_owned()]);
+        let (_, _, channels, _) = load_from(&cfg).unwrap();
+        assert_eq!(channels.0, vec!["#bevy".to_owned(), "#rust".to_owned()]);
+    }
+
+    #[test]
+    fn missing_channels_default_to_empty() {
+        let cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        let (_, _, channels, _) = load_from(&cfg).unwrap();
+        assert!(channels.0.is_empty());
+    }
+
+    #[test]
+    fn capabilities_are_always_empty() {
+        let cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        let (_, _, _, capabilities) = load_from(&cfg).unwrap();
+        assert!(capabilities.0.is_empty());
+    }
+
+    #[test]
+    fn ping_time_does_not_fail_loading_it_is_only_warned_about() {
+        let mut cfg = config(Some("irc.example.org"), Some("bevy_bot"));
+        cfg.ping_time = Some(120);
+        assert!(load_from(&cfg).is_ok());
+    }
+}
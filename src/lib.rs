@@ -8,6 +8,11 @@ pub use irc;
 
 /// Bevy components
 pub mod components;
+/// Loading [`Connection`](components::Connection)/[`Auth`](components::Auth)/
+/// [`Channels`](components::Channels) from `irc::Config` files
+pub mod config;
+/// Bevy observers reacting to incoming IRC messages
+mod observers;
 /// Bevy systems
 mod systems;
 /// Utilities for using the Twitch IRC
@@ -29,6 +34,11 @@ pub mod prelude {
 
 /// Bevy plugin to connect and manage IRC connections
 ///
+/// By default only the generic `Incoming<irc::Message>` event (plus `Incoming<Ctcp>` for CTCP
+/// queries and replies) is emitted; opt into strongly-typed per-command events with the `with_*_events` builder methods
+/// so handlers can `observe` exactly the command they care about instead of matching
+/// `msg.command` by hand.
+///
 /// # Example
 /// ```
 /// use bevy_irc::prelude::*;
@@ -42,9 +52,46 @@ pub mod prelude {
 ///     Channels(vec!["#bevy".to_owned()]),
 /// ));
 ///
-/// app.add_plugins(IRCPlugin);
+/// app.add_plugins(IRCPlugin::default().with_privmsg_events());
 /// ```
-pub struct IRCPlugin;
+#[derive(Default)]
+pub struct IRCPlugin {
+    typed_events: components::TypedEvents,
+}
+
+impl IRCPlugin {
+    /// Also emit `Incoming<PrivMsg>` for `PRIVMSG`s
+    #[inline]
+    #[must_use]
+    pub fn with_privmsg_events(mut self) -> Self {
+        self.typed_events.privmsg = true;
+        self
+    }
+
+    /// Also emit `Incoming<Join>` for `JOIN`s
+    #[inline]
+    #[must_use]
+    pub fn with_join_events(mut self) -> Self {
+        self.typed_events.join = true;
+        self
+    }
+
+    /// Also emit `Incoming<NickChange>` for `NICK` changes
+    #[inline]
+    #[must_use]
+    pub fn with_nick_change_events(mut self) -> Self {
+        self.typed_events.nick_change = true;
+        self
+    }
+
+    /// Also emit `Incoming<Numeric>` for numeric replies
+    #[inline]
+    #[must_use]
+    pub fn with_numeric_events(mut self) -> Self {
+        self.typed_events.numeric = true;
+        self
+    }
+}
 
 impl bevy_app::Plugin for IRCPlugin {
     fn build(&self, app: &mut bevy_app::App) {
@@ -54,22 +101,44 @@ impl bevy_app::Plugin for IRCPlugin {
             app.add_plugins(bevy_time::TimePlugin);
         }
 
+        app.insert_resource(self.typed_events);
         app.add_event::<components::Incoming>();
+        app.add_event::<components::Disconnected>();
+        app.add_event::<components::SaslFailed>();
+        app.add_event::<components::HistoryBatch>();
+        app.add_event::<components::Incoming<components::Ctcp>>();
+        app.add_event::<components::Incoming<components::PrivMsg>>();
+        app.add_event::<components::Incoming<components::Join>>();
+        app.add_event::<components::Incoming<components::NickChange>>();
+        app.add_event::<components::Incoming<components::Numeric>>();
         app.world_mut()
-            .observe(systems::send::<irc_prelude::Message>);
+            .observe(observers::send::<irc_prelude::Message>);
         app.world_mut()
-            .observe(systems::send::<irc_prelude::Command>);
+            .observe(observers::send::<irc_prelude::Command>);
+        app.world_mut().observe(observers::on_ping);
+        app.world_mut().observe(observers::on_welcome);
+        app.world_mut().observe(observers::on_cap);
+        app.world_mut().observe(observers::on_authenticate);
+        app.world_mut().observe(observers::on_sasl_numeric);
+        app.world_mut().observe(observers::on_request_history);
+        app.world_mut().observe(observers::on_ctcp);
+        app.world_mut().observe(observers::dispatch_typed_events);
 
         app.add_systems(
             Update,
             (
                 systems::connect,
                 systems::poll_connecting,
+                systems::schedule_reconnect,
+                systems::tick_reconnect,
+                systems::negotiate_capabilities,
+                systems::tick_cap_negotiation,
                 systems::identify,
                 systems::request_capabilities,
                 systems::join_channels,
                 systems::poll_stream,
                 systems::ping,
+                systems::drain_send_queue,
             ),
         );
     }
@@ -83,7 +152,7 @@ mod tests {
         let mut app = bevy_app::App::new();
         app.add_plugins(bevy_log::LogPlugin::default());
         // app.add_plugins(bevy_app::ScheduleRunnerPlugin::default());
-        app.add_plugins(IRCPlugin);
+        app.add_plugins(IRCPlugin::default());
 
         app.world_mut().spawn((
             Connection::new("irc.example.com", 6667),
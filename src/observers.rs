@@ -1,25 +1,26 @@
 #[allow(clippy::wildcard_imports)]
 use crate::components::*;
+use crate::systems::SASL_CHUNK_LEN;
 use bevy_ecs::prelude::*;
-use bevy_utils::tracing::{debug, error, info, trace};
+use bevy_utils::tracing::{debug, error, info, trace, warn};
 
 use crate::irc_prelude as irc;
 
-pub fn send(trigger: Trigger<Outgoing>, sender: Query<&Sender>, mut commands: Commands) {
-    let msg = &trigger.event().0;
+/// Queues an outgoing message for release by [`crate::systems::drain_send_queue`] instead of
+/// writing it to the `Sender` immediately, so a token-bucket governor can pace it
+pub fn send<T>(trigger: Trigger<Outgoing<T>>, mut queues: Query<&mut SendQueue>)
+where
+    T: Clone + Into<irc::Message> + Send + Sync + 'static,
+{
     let id = trigger.entity();
-    let sender = match sender.get(id) {
-        Ok(sender) => sender,
-        Err(e) => {
-            error!(message = "Failed to get sender", error=%e);
-            return;
-        }
+    let message: irc::Message = trigger.event().0.clone().into();
+    let class = CommandClass::of(&message.command);
+    let Ok(mut queue) = queues.get_mut(id) else {
+        error!(message = "Failed to queue outgoing message: no SendQueue", entity = ?id);
+        return;
     };
-    trace!(message = "Sending message", ?msg);
-    if let Err(e) = sender.send(msg.to_owned()) {
-        error!(message = "Failed to send message", error=%e);
-        commands.entity(id).remove::<Sender>();
-    }
+    trace!(message = "Queueing message", ?message);
+    queue.push(class, message);
 }
 
 pub fn on_ping(trigger: Trigger<Incoming>, mut commands: Commands) {
@@ -41,7 +42,541 @@ pub fn on_welcome(trigger: Trigger<Incoming>, mut commands: Commands) {
         );
         if let Some(mut entity) = commands.get_entity(trigger.entity()) {
             entity.remove::<Identifying>();
+            entity.remove::<ReconnectAttempts>();
             entity.insert(Registered);
         }
     }
 }
+
+/// Ends capability negotiation, letting registration proceed
+pub(crate) fn end_cap_negotiation(commands: &mut Commands, id: Entity) {
+    commands.trigger_targets(Outgoing::new(irc::Command::CAP(None, irc::CapSubCommand::END, None, None)), id);
+    if let Some(mut entity) = commands.get_entity(id) {
+        entity.remove::<NegotiatingCaps>();
+        entity.remove::<Authenticating>();
+        entity.remove::<CapLsBuffer>();
+        entity.insert(CapsNegotiated);
+    }
+}
+
+pub fn on_cap(
+    trigger: Trigger<Incoming>,
+    sasl: Query<&Sasl>,
+    mut buffers: Query<&mut CapLsBuffer>,
+    mut acked_capabilities: Query<&mut AckedCapabilities>,
+    mut commands: Commands,
+) {
+    let msg = &trigger.event().0;
+    let id = trigger.entity();
+    let irc::Command::CAP(_, sub, param, caps) = &msg.command else {
+        return;
+    };
+
+    match sub {
+        irc::CapSubCommand::LS => {
+            if let Ok(mut buffer) = buffers.get_mut(id) {
+                buffer
+                    .0
+                    .extend(caps.as_deref().unwrap_or_default().split_whitespace().map(ToOwned::to_owned));
+            }
+
+            // a `*` as the first parameter marks a multi-line LS response; wait for the final line
+            if param.as_deref() == Some("*") {
+                return;
+            }
+
+            let has_sasl_cap = buffers
+                .get(id)
+                .is_ok_and(|buf| buf.0.iter().any(|cap| cap == "sasl"));
+
+            if has_sasl_cap && sasl.get(id).is_ok() {
+                // only request `sasl` if the server actually advertised it
+                commands.trigger_targets(
+                    Outgoing::new(irc::Command::CAP(
+                        None,
+                        irc::CapSubCommand::REQ,
+                        None,
+                        Some("sasl".to_owned()),
+                    )),
+                    id,
+                );
+            } else {
+                end_cap_negotiation(&mut commands, id);
+            }
+        }
+        irc::CapSubCommand::ACK => {
+            let acked: Vec<_> = caps
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(ToOwned::to_owned)
+                .collect();
+            if let Ok(mut acked_caps) = acked_capabilities.get_mut(id) {
+                acked_caps.0.extend(acked.iter().cloned());
+            }
+
+            if !acked.iter().any(|c| c == "sasl") {
+                return;
+            }
+            let Ok(sasl) = sasl.get(id) else {
+                end_cap_negotiation(&mut commands, id);
+                return;
+            };
+            debug!(message = "SASL capability acknowledged, authenticating", mechanism = ?sasl.mechanism);
+            commands.entity(id).insert(Authenticating);
+            let authenticate = irc::Command::Raw(
+                "AUTHENTICATE".to_owned(),
+                vec![sasl.mechanism.as_str().to_owned()],
+            );
+            commands.trigger_targets(Outgoing::new(authenticate), id);
+        }
+        irc::CapSubCommand::NAK => {
+            debug!(message = "Capability request rejected", caps = ?caps);
+            end_cap_negotiation(&mut commands, id);
+        }
+        _ => {}
+    }
+}
+
+pub fn on_authenticate(trigger: Trigger<Incoming>, sasl: Query<&Sasl>, mut commands: Commands) {
+    let msg = &trigger.event().0;
+    let id = trigger.entity();
+    let irc::Command::Raw(cmd, args) = &msg.command else {
+        return;
+    };
+    if cmd != "AUTHENTICATE" || args.first().map(String::as_str) != Some("+") {
+        return;
+    }
+    let Ok(sasl) = sasl.get(id) else {
+        return;
+    };
+
+    let response = sasl.encode_response();
+    for chunk in sasl_auth_chunks(&response) {
+        commands.trigger_targets(
+            Outgoing::new(irc::Command::Raw("AUTHENTICATE".to_owned(), vec![chunk])),
+            id,
+        );
+    }
+}
+
+/// Splits a base64-encoded SASL response into `AUTHENTICATE` payload chunks of at most
+/// `SASL_CHUNK_LEN` bytes each, appending the empty-line sentinel (`+`) required by the spec
+/// when the payload is empty or an exact multiple of the chunk size
+fn sasl_auth_chunks(response: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = response
+        .as_bytes()
+        .chunks(SASL_CHUNK_LEN)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect();
+    if chunks.is_empty() || response.len() % SASL_CHUNK_LEN == 0 {
+        chunks.push("+".to_owned());
+    }
+    chunks
+}
+
+pub fn on_sasl_numeric(trigger: Trigger<Incoming>, mut commands: Commands, mut failed: EventWriter<SaslFailed>) {
+    use irc::Response::{
+        ERR_NICKLOCKED, ERR_SASLABORTED, ERR_SASLALREADY, ERR_SASLFAIL, ERR_SASLTOOLONG,
+        RPL_LOGGEDIN, RPL_SASLSUCCESS,
+    };
+
+    let msg = &trigger.event().0;
+    let id = trigger.entity();
+    let irc::Command::Response(numeric, _) = &msg.command else {
+        return;
+    };
+
+    match numeric {
+        RPL_LOGGEDIN | RPL_SASLSUCCESS => {
+            info!(message = "SASL authentication succeeded");
+            end_cap_negotiation(&mut commands, id);
+        }
+        ERR_NICKLOCKED | ERR_SASLFAIL | ERR_SASLTOOLONG | ERR_SASLABORTED | ERR_SASLALREADY => {
+            error!(message = "SASL authentication failed", numeric = ?numeric);
+            failed.send(SaslFailed { numeric: *numeric });
+            end_cap_negotiation(&mut commands, id);
+        }
+        _ => {}
+    }
+}
+
+/// Capabilities required for CHATHISTORY requests to be meaningful: `draft/chathistory` for the
+/// command itself, `batch` so the reply can be collected, and `server-time` so replayed messages
+/// carry their original time
+const CHATHISTORY_CAPS: [&str; 3] = ["draft/chathistory", "server-time", "batch"];
+
+pub fn on_request_history(
+    trigger: Trigger<RequestHistory>,
+    acked: Query<&AckedCapabilities>,
+    mut commands: Commands,
+) {
+    let id = trigger.entity();
+    let has_caps = acked.get(id).is_ok_and(|acked| {
+        CHATHISTORY_CAPS
+            .iter()
+            .all(|required| acked.0.iter().any(|cap| cap == required))
+    });
+    if !has_caps {
+        warn!(
+            message = "Cannot request history: required capabilities not negotiated",
+            entity = ?id,
+            required = ?CHATHISTORY_CAPS,
+        );
+        return;
+    }
+
+    let request = trigger.event().clone().into_command();
+    commands.trigger_targets(Outgoing::new(request), id);
+}
+
+/// Parses CTCP queries/replies out of `PRIVMSG`/`NOTICE` trailing parameters wrapped in `\x01`,
+/// emits a typed `Incoming<Ctcp>` alongside the raw `Incoming<Message>`, and auto-replies to the
+/// standard queries per the entity's `CtcpConfig`, if any
+pub fn on_ctcp(
+    trigger: Trigger<Incoming>,
+    configs: Query<&CtcpConfig>,
+    mut ctcp_events: EventWriter<Incoming<Ctcp>>,
+    mut commands: Commands,
+) {
+    let msg = &trigger.event().0;
+    let id = trigger.entity();
+
+    let (target, text, is_reply) = match &msg.command {
+        irc::Command::PRIVMSG(target, text) => (target, text, false),
+        irc::Command::NOTICE(target, text) => (target, text, true),
+        _ => return,
+    };
+
+    let Some(payload) = text.strip_prefix('\u{1}').and_then(|s| s.strip_suffix('\u{1}')) else {
+        return;
+    };
+
+    let command = CtcpCommand::parse(&ctcp_dequote(payload));
+    let from = msg.source_nickname().map(ToOwned::to_owned);
+    debug!(message = "Received CTCP", ?command, is_reply, ?from);
+
+    let event = Ctcp {
+        target: target.clone(),
+        from: from.clone(),
+        is_reply,
+        command: command.clone(),
+    };
+    commands.trigger_targets(Incoming(event.clone()), id);
+    ctcp_events.send(Incoming(event));
+
+    // CTCP replies are never themselves replied to, to avoid a reply loop between two bots
+    if is_reply {
+        return;
+    }
+
+    let Ok(config) = configs.get(id) else {
+        return;
+    };
+    let Some(reply) = config.reply_for(&command) else {
+        return;
+    };
+    let Some(reply_target) = from else {
+        return;
+    };
+
+    let notice = irc::Command::NOTICE(reply_target, ctcp_wrap(&reply.encode()));
+    commands.trigger_targets(Outgoing::new(notice), id);
+}
+
+/// Routes the generic `Incoming<Message>` to the strongly-typed `Incoming<T>` events opted into
+/// via [`crate::IRCPlugin`]'s builder methods, so handlers can `observe` exactly the command they
+/// care about instead of matching `msg.command` by hand
+pub fn dispatch_typed_events(
+    trigger: Trigger<Incoming>,
+    typed: Res<TypedEvents>,
+    mut commands: Commands,
+    mut privmsg: EventWriter<Incoming<PrivMsg>>,
+    mut joins: EventWriter<Incoming<Join>>,
+    mut nick_changes: EventWriter<Incoming<NickChange>>,
+    mut numerics: EventWriter<Incoming<Numeric>>,
+) {
+    let msg = &trigger.event().0;
+    let id = trigger.entity();
+    let from = || msg.source_nickname().map(ToOwned::to_owned);
+
+    match &msg.command {
+        irc::Command::PRIVMSG(target, text) if typed.privmsg => {
+            let event = PrivMsg {
+                target: target.clone(),
+                text: text.clone(),
+                from: from(),
+            };
+            commands.trigger_targets(Incoming(event.clone()), id);
+            privmsg.send(Incoming(event));
+        }
+        irc::Command::JOIN(channel, _, _) if typed.join => {
+            let event = Join {
+                channel: channel.clone(),
+                who: from(),
+            };
+            commands.trigger_targets(Incoming(event.clone()), id);
+            joins.send(Incoming(event));
+        }
+        irc::Command::NICK(new_nick) if typed.nick_change => {
+            let event = NickChange {
+                old_nick: from(),
+                new_nick: new_nick.clone(),
+            };
+            commands.trigger_targets(Incoming(event.clone()), id);
+            nick_changes.send(Incoming(event));
+        }
+        irc::Command::Response(numeric, args) if typed.numeric => {
+            let event = Numeric {
+                numeric: *numeric,
+                args: args.clone(),
+            };
+            commands.trigger_targets(Incoming(event.clone()), id);
+            numerics.send(Incoming(event));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sasl_auth_chunks;
+    use crate::systems::SASL_CHUNK_LEN;
+
+    #[test]
+    fn empty_response_sends_a_single_sentinel() {
+        assert_eq!(sasl_auth_chunks(""), vec!["+".to_owned()]);
+    }
+
+    #[test]
+    fn response_shorter_than_chunk_len_is_not_terminated() {
+        let response = "a".repeat(SASL_CHUNK_LEN - 1);
+        assert_eq!(sasl_auth_chunks(&response), vec![response]);
+    }
+
+    #[test]
+    fn response_exactly_one_chunk_is_terminated_with_sentinel() {
+        let response = "a".repeat(SASL_CHUNK_LEN);
+        assert_eq!(
+            sasl_auth_chunks(&response),
+            vec![response, "+".to_owned()]
+        );
+    }
+
+    #[test]
+    fn response_spanning_multiple_chunks_is_split_and_not_terminated() {
+        let response = "a".repeat(SASL_CHUNK_LEN + 10);
+        let chunks = sasl_auth_chunks(&response);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), SASL_CHUNK_LEN);
+        assert_eq!(chunks[1].len(), 10);
+    }
+
+    #[test]
+    fn response_exactly_two_chunks_is_terminated_with_sentinel() {
+        let response = "a".repeat(SASL_CHUNK_LEN * 2);
+        let chunks = sasl_auth_chunks(&response);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2], "+");
+    }
+
+    mod dispatch_typed_events {
+        use super::super::dispatch_typed_events;
+        use crate::components::{Incoming, Join, NickChange, Numeric, PrivMsg, TypedEvents};
+        use crate::irc_prelude as irc;
+        use bevy_ecs::event::Events;
+        use bevy_ecs::prelude::*;
+
+        fn world_with(typed: TypedEvents) -> World {
+            let mut world = World::new();
+            world.insert_resource(typed);
+            world.init_resource::<Events<Incoming<PrivMsg>>>();
+            world.init_resource::<Events<Incoming<Join>>>();
+            world.init_resource::<Events<Incoming<NickChange>>>();
+            world.init_resource::<Events<Incoming<Numeric>>>();
+            world.observe(dispatch_typed_events);
+            world
+        }
+
+        fn send(world: &mut World, command: irc::Command) {
+            let id = world.spawn_empty().id();
+            let msg = irc::Message {
+                tags: None,
+                prefix: None,
+                command,
+            };
+            world.trigger_targets(Incoming(msg), id);
+        }
+
+        fn drain<E: Event>(world: &mut World) -> Vec<E> {
+            world.resource_mut::<Events<E>>().drain().collect()
+        }
+
+        #[test]
+        fn privmsg_is_dispatched_when_opted_in() {
+            let mut world = world_with(TypedEvents {
+                privmsg: true,
+                ..TypedEvents::default()
+            });
+            send(&mut world, irc::Command::PRIVMSG("#bevy".to_owned(), "hi".to_owned()));
+            let events = drain::<Incoming<PrivMsg>>(&mut world);
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].0.target, "#bevy");
+            assert_eq!(events[0].0.text, "hi");
+        }
+
+        #[test]
+        fn privmsg_is_not_dispatched_when_opted_out() {
+            let mut world = world_with(TypedEvents::default());
+            send(&mut world, irc::Command::PRIVMSG("#bevy".to_owned(), "hi".to_owned()));
+            assert!(drain::<Incoming<PrivMsg>>(&mut world).is_empty());
+        }
+
+        #[test]
+        fn join_is_dispatched_when_opted_in() {
+            let mut world = world_with(TypedEvents {
+                join: true,
+                ..TypedEvents::default()
+            });
+            send(&mut world, irc::Command::JOIN("#bevy".to_owned(), None, None));
+            let events = drain::<Incoming<Join>>(&mut world);
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].0.channel, "#bevy");
+        }
+
+        #[test]
+        fn nick_change_is_dispatched_when_opted_in() {
+            let mut world = world_with(TypedEvents {
+                nick_change: true,
+                ..TypedEvents::default()
+            });
+            send(&mut world, irc::Command::NICK("new_nick".to_owned()));
+            let events = drain::<Incoming<NickChange>>(&mut world);
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].0.new_nick, "new_nick");
+        }
+
+        #[test]
+        fn numeric_is_dispatched_when_opted_in() {
+            let mut world = world_with(TypedEvents {
+                numeric: true,
+                ..TypedEvents::default()
+            });
+            send(
+                &mut world,
+                irc::Command::Response(irc::Response::RPL_WELCOME, vec!["hi".to_owned()]),
+            );
+            let events = drain::<Incoming<Numeric>>(&mut world);
+            assert_eq!(events.len(), 1);
+            assert!(matches!(events[0].0.numeric, irc::Response::RPL_WELCOME));
+            assert_eq!(events[0].0.args, vec!["hi".to_owned()]);
+        }
+
+        #[test]
+        fn an_unmatched_command_dispatches_nothing() {
+            let mut world = world_with(TypedEvents {
+                privmsg: true,
+                join: true,
+                nick_change: true,
+                numeric: true,
+            });
+            send(&mut world, irc::Command::PART("#bevy".to_owned(), None));
+            assert!(drain::<Incoming<PrivMsg>>(&mut world).is_empty());
+            assert!(drain::<Incoming<Join>>(&mut world).is_empty());
+            assert!(drain::<Incoming<NickChange>>(&mut world).is_empty());
+            assert!(drain::<Incoming<Numeric>>(&mut world).is_empty());
+        }
+    }
+
+    mod on_ctcp {
+        use super::super::on_ctcp;
+        use crate::components::{Ctcp, CtcpConfig, Incoming, Outgoing};
+        use crate::irc_prelude as irc;
+        use bevy_ecs::event::Events;
+        use bevy_ecs::prelude::*;
+
+        #[derive(Resource, Default)]
+        struct Sent(Vec<irc::Command>);
+
+        fn record_sent(trigger: Trigger<Outgoing<irc::Command>>, mut sent: ResMut<Sent>) {
+            sent.0.push(trigger.event().0.clone());
+        }
+
+        fn world_with(config: Option<CtcpConfig>) -> (World, Entity) {
+            let mut world = World::new();
+            world.init_resource::<Events<Incoming<Ctcp>>>();
+            world.init_resource::<Sent>();
+            world.observe(on_ctcp);
+            world.observe(record_sent);
+            let id = match config {
+                Some(config) => world.spawn(config).id(),
+                None => world.spawn_empty().id(),
+            };
+            (world, id)
+        }
+
+        fn ctcp_message(payload: &str, is_reply: bool) -> irc::Message {
+            let prefix = Some(crate::irc::proto::Prefix::Nickname(
+                "alice".to_owned(),
+                "alice".to_owned(),
+                "host".to_owned(),
+            ));
+            let wrapped = format!("\u{1}{payload}\u{1}");
+            let command = if is_reply {
+                irc::Command::NOTICE("#bevy".to_owned(), wrapped)
+            } else {
+                irc::Command::PRIVMSG("#bevy".to_owned(), wrapped)
+            };
+            irc::Message {
+                tags: None,
+                prefix,
+                command,
+            }
+        }
+
+        #[test]
+        fn a_standard_query_is_auto_replied_to_via_notice() {
+            let (mut world, id) = world_with(Some(CtcpConfig::new("bevy_irc 0.1")));
+            world.trigger_targets(Incoming(ctcp_message("PING 1234", false)), id);
+
+            let sent = &world.resource::<Sent>().0;
+            assert_eq!(sent.len(), 1);
+            assert!(matches!(&sent[0], irc::Command::NOTICE(target, text) if target == "alice" && text.contains("PING 1234")));
+        }
+
+        #[test]
+        fn a_ctcp_reply_is_never_itself_replied_to() {
+            let (mut world, id) = world_with(Some(CtcpConfig::new("bevy_irc 0.1")));
+            world.trigger_targets(Incoming(ctcp_message("PING 1234", true)), id);
+
+            assert!(world.resource::<Sent>().0.is_empty());
+        }
+
+        #[test]
+        fn without_a_ctcp_config_nothing_is_auto_replied_to() {
+            let (mut world, id) = world_with(None);
+            world.trigger_targets(Incoming(ctcp_message("VERSION", false)), id);
+
+            assert!(world.resource::<Sent>().0.is_empty());
+        }
+
+        #[test]
+        fn a_disabled_query_is_not_auto_replied_to() {
+            let (mut world, id) = world_with(Some(CtcpConfig::new("bevy_irc 0.1").reply_version(false)));
+            world.trigger_targets(Incoming(ctcp_message("VERSION", false)), id);
+
+            assert!(world.resource::<Sent>().0.is_empty());
+        }
+
+        #[test]
+        fn ctcp_is_still_emitted_as_a_typed_event_even_without_a_config() {
+            let (mut world, id) = world_with(None);
+            world.trigger_targets(Incoming(ctcp_message("VERSION", false)), id);
+
+            let events = world.resource_mut::<Events<Incoming<Ctcp>>>().drain().collect::<Vec<_>>();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].0.target, "#bevy");
+            assert!(!events[0].0.is_reply);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 #[allow(clippy::wildcard_imports)]
 use crate::components::*;
+use std::time::Duration;
+
 use async_compat::CompatExt;
 use bevy_ecs::prelude::*;
 use bevy_time::{Real, Time};
@@ -9,15 +11,20 @@ use bevy_utils::{
 };
 
 use crate::irc_prelude as irc;
+use ::irc::proto::message::Tag;
 
 pub fn connect(
     mut commands: Commands,
     chats: Query<
-        (Entity, &Connection),
-        (Without<Connecting>, Or<(Without<Sender>, Without<Stream>)>),
+        (Entity, &Connection, Option<&RateLimit>),
+        (
+            Without<Connecting>,
+            Without<Reconnect>,
+            Or<(Without<Sender>, Without<Stream>)>,
+        ),
     >,
 ) {
-    for (id, con) in &chats {
+    for (id, con, rate_limit) in &chats {
         let mut entity = commands.entity(id);
         let config = con.into();
         info!(message = "Connecting", ?config);
@@ -25,7 +32,16 @@ pub fn connect(
         // let fut = Box::pin(fut);
         // let fut = Compat::new(boxed_fut);
         let connecting = Connecting::new(fut);
-        entity.insert((connecting, Pinger::default()));
+        let rate_limit = rate_limit.cloned().unwrap_or_else(|| con.default_rate_limit());
+        entity.insert((
+            connecting,
+            Pinger::default(),
+            AckedCapabilities::default(),
+            OpenBatches::default(),
+            SendQueue::default(),
+            BucketStates::new(&rate_limit),
+            rate_limit,
+        ));
         entity.remove::<Registered>();
     }
 }
@@ -42,6 +58,10 @@ pub fn poll_connecting(mut commands: Commands, mut chats: Query<(Entity, &mut Co
                     info!(message = "Connected", ?client);
                     entity.insert(Sender(client.sender()));
                     entity.insert(Stream(client.stream().unwrap()));
+                    entity.remove::<NegotiatingCaps>();
+                    entity.remove::<CapsNegotiated>();
+                    entity.remove::<Authenticating>();
+                    entity.remove::<CapLsBuffer>();
                 }
                 Err(e) => {
                     error!(message = "Failed to connect", error=%e);
@@ -52,6 +72,55 @@ pub fn poll_connecting(mut commands: Commands, mut chats: Query<(Entity, &mut Co
     }
 }
 
+pub fn schedule_reconnect(
+    mut commands: Commands,
+    mut lost_senders: RemovedComponents<Sender>,
+    mut lost_streams: RemovedComponents<Stream>,
+    chats: Query<
+        Option<&ReconnectAttempts>,
+        (With<Connection>, Without<Connecting>, Without<Reconnect>),
+    >,
+    policies: Query<&ReconnectPolicy>,
+    mut disconnected: EventWriter<Disconnected>,
+) {
+    let ids: std::collections::HashSet<_> = lost_senders.read().chain(lost_streams.read()).collect();
+    for id in ids {
+        let Ok(attempts) = chats.get(id) else {
+            continue;
+        };
+        let attempt = attempts.map_or(0, |a| a.0);
+        disconnected.send(Disconnected { entity: id });
+        if let Ok(policy) = policies.get(id) {
+            if policy.max_attempts.is_some_and(|max| attempt >= max) {
+                warn!(message = "Giving up reconnecting", entity = ?id, attempt);
+                continue;
+            }
+        }
+        info!(message = "Connection lost, scheduling reconnect", entity = ?id, attempt);
+        commands.entity(id).insert((
+            Reconnect {
+                timer: bevy_time::Stopwatch::new(),
+                attempt,
+            },
+            ReconnectAttempts(attempt + 1),
+        ));
+    }
+}
+
+pub fn tick_reconnect(
+    mut commands: Commands,
+    mut chats: Query<(Entity, &mut Reconnect, Option<&ReconnectPolicy>)>,
+    time: Res<Time<Real>>,
+) {
+    for (id, mut reconnect, policy) in &mut chats {
+        reconnect.timer.tick(time.delta());
+        let delay = policy.cloned().unwrap_or_default().delay_for(reconnect.attempt);
+        if reconnect.timer.elapsed() >= delay {
+            commands.entity(id).remove::<Reconnect>();
+        }
+    }
+}
+
 pub fn ping(
     mut pingers: Query<(Entity, &mut Pinger)>,
     time: Res<Time<Real>>,
@@ -67,9 +136,66 @@ pub fn ping(
     }
 }
 
+/// Maximum length (in bytes) of a single `AUTHENTICATE` payload line, per IRCv3
+pub(crate) const SASL_CHUNK_LEN: usize = 400;
+
+pub fn negotiate_capabilities(
+    mut commands: Commands,
+    chats: Query<
+        Entity,
+        (
+            With<Sender>,
+            Without<Registered>,
+            Without<NegotiatingCaps>,
+            Without<CapsNegotiated>,
+        ),
+    >,
+) {
+    for id in &chats {
+        info!(message = "Negotiating capabilities", entity = ?id);
+        commands
+            .entity(id)
+            .insert((NegotiatingCaps::default(), CapLsBuffer::default()));
+        let ls = irc::Command::CAP(None, irc::CapSubCommand::LS, Some("302".to_owned()), None);
+        commands.trigger_targets(Outgoing::new(ls), id);
+    }
+}
+
+/// How long capability negotiation (from `CAP LS` through any SASL exchange) may take before it
+/// is ended and registration proceeds unconditionally
+pub(crate) const CAP_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Ends capability negotiation once it has run for `CAP_NEGOTIATION_TIMEOUT`, whether the server
+/// never replied to `CAP LS` or a later step (`CAP ACK`, SASL) stalled, so the connection doesn't
+/// stay stuck forever waiting for `CapsNegotiated`
+pub fn tick_cap_negotiation(
+    mut commands: Commands,
+    mut chats: Query<(Entity, &mut NegotiatingCaps)>,
+    time: Res<Time<Real>>,
+) {
+    for (id, mut negotiating) in &mut chats {
+        negotiating.timer.tick(time.delta());
+        if negotiating.timer.elapsed() >= CAP_NEGOTIATION_TIMEOUT {
+            warn!(
+                message = "Server did not respond to capability negotiation in time, registering anyway",
+                entity = ?id,
+            );
+            crate::observers::end_cap_negotiation(&mut commands, id);
+        }
+    }
+}
+
 pub fn identify(
     mut commands: Commands,
-    chats: Query<(Entity, &Auth), (With<Sender>, Without<Registered>, Without<Identifying>)>,
+    chats: Query<
+        (Entity, &Auth),
+        (
+            With<Sender>,
+            With<CapsNegotiated>,
+            Without<Registered>,
+            Without<Identifying>,
+        ),
+    >,
 ) {
     for (id, auth) in &chats {
         commands.entity(id).insert(Identifying);
@@ -123,11 +249,12 @@ pub fn request_capabilities(
 
 pub fn poll_stream(
     mut commands: Commands,
-    mut streams: Query<(Entity, &mut Stream)>,
+    mut streams: Query<(Entity, &mut Stream, &mut OpenBatches)>,
     mut incoming: EventWriter<Incoming>,
+    mut history: EventWriter<HistoryBatch>,
 ) {
     use futures_util::StreamExt;
-    for (id, mut stream) in &mut streams {
+    for (id, mut stream, mut batches) in &mut streams {
         loop {
             let Some(next) = now_or_never(stream.0.next()) else {
                 break;
@@ -140,6 +267,30 @@ pub fn poll_stream(
                 }
                 Some(Ok(msg)) => {
                     trace!(message = "Received message", ?msg);
+
+                    if let irc::Command::Raw(cmd, args) = &msg.command {
+                        if cmd == "BATCH" {
+                            handle_batch_control(&mut batches, args, &mut history);
+                            continue;
+                        }
+                    }
+
+                    let mut batch_ref = None;
+                    if let Some(tags) = &msg.tags {
+                        for Tag(key, val) in tags {
+                            if key == "batch" {
+                                batch_ref.clone_from(val);
+                            }
+                        }
+                    }
+                    if let Some(batch_ref) = batch_ref {
+                        if let Some(batch) = batches.0.get_mut(&batch_ref) {
+                            batch.messages.push(msg);
+                            continue;
+                        }
+                        // unknown/nested batch we're not collecting: pass through untouched
+                    }
+
                     commands.trigger_targets(Incoming(msg.clone()), id);
                     incoming.send(Incoming(msg));
                 }
@@ -152,3 +303,152 @@ pub fn poll_stream(
         }
     }
 }
+
+/// Tracks `BATCH +<ref> chathistory <target>` / `BATCH -<ref>` control lines, opening and
+/// closing the corresponding entry in `OpenBatches`
+fn handle_batch_control(batches: &mut OpenBatches, args: &[String], history: &mut EventWriter<HistoryBatch>) {
+    let Some(marker) = args.first() else {
+        return;
+    };
+    if let Some(reference) = marker.strip_prefix('+') {
+        if args.get(1).map(String::as_str) == Some("chathistory") {
+            let target = args.get(2).cloned().unwrap_or_default();
+            batches.0.insert(
+                reference.to_owned(),
+                OpenBatch {
+                    target,
+                    messages: Vec::new(),
+                },
+            );
+        }
+        // other batch types aren't chathistory and are left untracked, passing through untouched
+    } else if let Some(reference) = marker.strip_prefix('-') {
+        if let Some(batch) = batches.0.remove(reference) {
+            history.send(HistoryBatch {
+                target: batch.target,
+                messages: batch.messages,
+            });
+        }
+    }
+}
+
+/// Refills each entity's token buckets and releases queued messages to the `Sender` as capacity
+/// allows, pacing outgoing traffic so the connection doesn't trip a network's flood protection
+pub fn drain_send_queue(
+    mut commands: Commands,
+    mut queues: Query<(Entity, &mut SendQueue, &mut BucketStates, &Sender, Option<&RateLimit>)>,
+    time: Res<Time<Real>>,
+) {
+    let elapsed = time.delta();
+    for (id, mut queue, mut buckets, sender, rate_limit) in &mut queues {
+        let rate_limit = rate_limit.cloned().unwrap_or_default();
+        buckets.refill(&rate_limit, elapsed);
+
+        // Drain each class's queue independently so a class whose bucket is empty doesn't
+        // gate messages of a different class that still has tokens available.
+        'classes: for class in [CommandClass::Message, CommandClass::Join, CommandClass::Generic] {
+            while queue.queue_mut(class).front().is_some() {
+                if !buckets.try_take(class) {
+                    break;
+                }
+                let message = queue
+                    .queue_mut(class)
+                    .pop_front()
+                    .expect("front() just confirmed an entry");
+                trace!(message = "Releasing queued message", entity = ?id, ?message);
+                if let Err(e) = sender.send(message) {
+                    error!(message = "Failed to send message", entity = ?id, error=%e);
+                    commands.entity(id).remove::<Sender>();
+                    break 'classes;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_ecs::{event::Events, system::SystemState};
+
+    fn history_writer_state(world: &mut World) -> SystemState<EventWriter<'static, HistoryBatch>> {
+        world.init_resource::<Events<HistoryBatch>>();
+        SystemState::new(world)
+    }
+
+    fn drain_history(world: &mut World) -> Vec<HistoryBatch> {
+        world.resource_mut::<Events<HistoryBatch>>().drain().collect()
+    }
+
+    #[test]
+    fn handle_batch_control_opens_a_chathistory_batch_on_plus() {
+        let mut world = World::new();
+        let mut state = history_writer_state(&mut world);
+        let mut batches = OpenBatches::default();
+        let mut writer = state.get_mut(&mut world);
+        handle_batch_control(
+            &mut batches,
+            &["+ref1".to_owned(), "chathistory".to_owned(), "#bevy".to_owned()],
+            &mut writer,
+        );
+        assert_eq!(batches.0.get("ref1").map(|b| b.target.as_str()), Some("#bevy"));
+    }
+
+    #[test]
+    fn handle_batch_control_leaves_non_chathistory_batches_untracked() {
+        let mut world = World::new();
+        let mut state = history_writer_state(&mut world);
+        let mut batches = OpenBatches::default();
+        let mut writer = state.get_mut(&mut world);
+        handle_batch_control(&mut batches, &["+ref1".to_owned(), "netjoin".to_owned()], &mut writer);
+        assert!(batches.0.is_empty());
+    }
+
+    #[test]
+    fn handle_batch_control_closes_a_known_batch_and_emits_its_messages() {
+        let mut world = World::new();
+        let mut batches = OpenBatches::default();
+        batches.0.insert(
+            "ref1".to_owned(),
+            OpenBatch {
+                target: "#bevy".to_owned(),
+                messages: Vec::new(),
+            },
+        );
+        let mut state = history_writer_state(&mut world);
+        {
+            let mut writer = state.get_mut(&mut world);
+            handle_batch_control(&mut batches, &["-ref1".to_owned()], &mut writer);
+        }
+        state.apply(&mut world);
+
+        assert!(batches.0.is_empty());
+        let events = drain_history(&mut world);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].target, "#bevy");
+    }
+
+    #[test]
+    fn handle_batch_control_closing_an_unknown_reference_is_a_noop() {
+        let mut world = World::new();
+        let mut batches = OpenBatches::default();
+        let mut state = history_writer_state(&mut world);
+        {
+            let mut writer = state.get_mut(&mut world);
+            handle_batch_control(&mut batches, &["-unknown".to_owned()], &mut writer);
+        }
+        state.apply(&mut world);
+
+        assert!(drain_history(&mut world).is_empty());
+    }
+
+    #[test]
+    fn handle_batch_control_with_no_args_is_a_noop() {
+        let mut world = World::new();
+        let mut batches = OpenBatches::default();
+        let mut state = history_writer_state(&mut world);
+        let mut writer = state.get_mut(&mut world);
+        handle_batch_control(&mut batches, &[], &mut writer);
+        assert!(batches.0.is_empty());
+    }
+}